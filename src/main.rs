@@ -1,10 +1,12 @@
+use std::collections::HashMap;
 use std::fmt::Debug;
+use std::path::{Path, PathBuf};
 
-use chrono::NaiveDateTime;
+use chrono::{NaiveDateTime, Utc};
 use clap::{Parser, ValueEnum};
 use lettre::message::header::{self, ContentType, UserAgent};
 use lettre::message::Mailboxes;
-use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::authentication::{Credentials, Mechanism};
 use lettre::{Message, SmtpTransport, Transport};
 use serde::{Deserialize, Serialize};
 use strum_macros::{Display as EnumDisplay, EnumString};
@@ -61,15 +63,119 @@ struct Args {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct Config {
-    smtp: Smtp,
+    notifiers: Vec<NotifierConfig>,
+
+    /// When set, identical PROBLEM notifications seen within this many seconds
+    /// are suppressed. RECOVERY notifications are never suppressed.
+    #[serde(default)]
+    dedup_window_secs: Option<i64>,
+
+    /// Template file names that should be sent as `text/html` rather than
+    /// `text/plain`.
+    #[serde(default)]
+    html_templates: Vec<String>,
+}
+
+/// A single delivery backend. Variants are distinguished by the fields they
+/// carry (untagged), so `config.yml` can list heterogeneous blocks without a
+/// discriminator key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum NotifierConfig {
+    Email(Email),
+    Webhook(Webhook),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct Smtp {
+struct Email {
     host: String,
-    user_name: String,
-    password: String,
     from: String,
+    #[serde(default)]
+    user_name: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    port: Option<u16>,
+    #[serde(default)]
+    encryption: TlsMode,
+    #[serde(default)]
+    auth: Option<AuthMechanism>,
+}
+
+/// How the SMTP connection is secured. `Implicit` wraps TLS from the first
+/// byte (port 465), `StartTls` upgrades an initially plaintext connection in
+/// band (port 587), and `None` uses no TLS at all (port 25), as for an internal
+/// relay; credentials are still sent if `user_name` and `password` are set.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum TlsMode {
+    #[default]
+    Implicit,
+    StartTls,
+    None,
+}
+
+/// SMTP authentication mechanism, mapped onto lettre's own `Mechanism`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum AuthMechanism {
+    Plain,
+    Login,
+}
+
+impl From<AuthMechanism> for Mechanism {
+    fn from(mechanism: AuthMechanism) -> Self {
+        match mechanism {
+            AuthMechanism::Plain => Mechanism::Plain,
+            AuthMechanism::Login => Mechanism::Login,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Webhook {
+    url: String,
+}
+
+/// Whether a rendered body is plain text or HTML. Derived from which template
+/// was selected (see `html_templates` in `Config`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BodyType {
+    Text,
+    Html,
+}
+
+/// Something that can deliver a rendered event. Each backend owns its own
+/// credentials and decides how to turn the event into a request.
+trait Notifier {
+    fn deliver(&self, args: &Args, subject: &str, body: &str, body_type: BodyType)
+        -> Result<(), String>;
+}
+
+impl Notifier for NotifierConfig {
+    fn deliver(
+        &self,
+        args: &Args,
+        subject: &str,
+        body: &str,
+        body_type: BodyType,
+    ) -> Result<(), String> {
+        match self {
+            NotifierConfig::Email(email) => email.deliver(args, subject, body, body_type),
+            NotifierConfig::Webhook(webhook) => webhook.deliver(args, subject, body, body_type),
+        }
+    }
+}
+
+/// Payload POSTed to a webhook: the raw CLI `Args` plus the values we compute
+/// for e-mail, so Slack/Mattermost/PagerDuty-style endpoints get the same view.
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    #[serde(flatten)]
+    args: &'a Args,
+    title: String,
+    subject: &'a str,
+    status_description: Option<&'a str>,
 }
 
 #[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq, Serialize)]
@@ -122,20 +228,75 @@ fn main() {
 
     debug!("started");
 
+    // Replay anything parked by a previous invocation before doing new work.
+    drain_spool();
+
     let args = Args::parse();
 
-    let config = load_config().expect("failed to load config file");
+    // A malformed config is unrecoverable — we don't even know where to send.
+    // Record it so Nagios can distinguish misconfiguration (exit 2) from a
+    // mail-send failure (exit 1).
+    let config = match load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            error!(error = %e, host = %args.host, "config failed to parse; cannot notify");
+            ::std::process::exit(2);
+        }
+    };
 
-    let body = create_body(&args).expect("failed to create mail body");
+    // If rendering blows up we still have a usable config, so fall back to a
+    // degraded plain-text message built straight from the CLI args rather than
+    // leaving the on-call with nothing.
+    let (body, body_type) = match create_body(&args) {
+        Ok((body, template)) => {
+            let body_type = if config.html_templates.contains(&template) {
+                BodyType::Html
+            } else {
+                BodyType::Text
+            };
+            (body, body_type)
+        }
+        Err(e) => {
+            error!("failed to render template, sending degraded notification: {e}");
+            (fallback_body(&args), BodyType::Text)
+        }
+    };
 
     if args.verbose {
         println!("{}", &body);
     }
 
-    match send_mail(&config, &args, &body) {
-        Ok(()) => ::std::process::exit(0),
-        Err(_e) => ::std::process::exit(1),
+    // Squelch repeated PROBLEM notifications for an ongoing incident.
+    if let Some(window) = config.dedup_window_secs {
+        if suppress(&args, window) {
+            info!(
+                "suppressed duplicate {} notification for {}",
+                args.notification_type,
+                incident_key(&args)
+            );
+            ::std::process::exit(0);
+        }
     }
+
+    let subject = subject(&args);
+
+    info!("subject: {}", subject);
+
+    // Fan the same rendered event out to every configured notifier. Exit
+    // non-zero only if *every* delivery failed.
+    let mut delivered = 0;
+    for notifier in &config.notifiers {
+        match notifier.deliver(&args, &subject, &body, body_type) {
+            Ok(()) => delivered += 1,
+            Err(e) => error!("notifier failed: {e}"),
+        }
+    }
+
+    if delivered == 0 && !config.notifiers.is_empty() {
+        ::std::process::exit(1);
+    }
+
+    ::std::process::exit(0);
 }
 
 fn load_config() -> Result<Config, String> {
@@ -145,14 +306,10 @@ fn load_config() -> Result<Config, String> {
     serde_yaml::from_str(&str).map_err(|_| "".to_string())
 }
 
-fn create_body(args: &Args) -> tera::Result<String> {
-    let tera = match Tera::new("./*.txt") {
-        Ok(t) => t,
-        Err(e) => {
-            println!("Parsing error(s): {}", e);
-            ::std::process::exit(1);
-        }
-    };
+/// Render the body for an event, returning the rendered text alongside the
+/// template that produced it so the caller can decide the content type.
+fn create_body(args: &Args) -> tera::Result<(String, String)> {
+    let tera = Tera::new("./*.txt")?;
 
     let host_address = &args.clone().host_address.unwrap_or_else(|| "?".to_string());
 
@@ -164,57 +321,430 @@ fn create_body(args: &Args) -> tera::Result<String> {
     context.insert("host_address", host_address);
     context.insert("status_description", &status_description(args));
 
-    tera.render("template.txt", &context)
+    let template = select_template(&tera, args);
+
+    tera.render(&template, &context).map(|body| (body, template))
 }
 
-fn send_mail(
-    config: &Config,
-    args: &Args,
-    body: &str,
-) -> Result<(), lettre::transport::smtp::Error> {
-    let from = config.smtp.from.clone();
+/// A minimal, template-free body assembled from the raw CLI args, used when
+/// the configured template cannot be rendered so the on-call still learns that
+/// something fired — and that the notifier itself is degraded.
+fn fallback_body(args: &Args) -> String {
+    format!(
+        "[{}] テンプレートの描画に失敗したため簡易通知を送信しています。\n\n\
+         ホスト: {}\n\
+         サービス: {}\n\
+         ステータス: {}\n\
+         出力:\n{}\n",
+        args.notification_type,
+        args.host,
+        args.service.clone().unwrap_or_else(|| "-".to_string()),
+        args.status
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| "-".to_string()),
+        args.output.clone().unwrap_or_else(|| "-".to_string()),
+    )
+}
 
-    let mailboxes: Mailboxes = args.addresses.parse().unwrap();
-    let to_header: header::To = mailboxes.into();
+/// Resolve the most specific available template for an event: prefer
+/// `{type}-{notification_type}.txt`, then `{notification_type}.txt`, then the
+/// generic `template.txt`.
+fn select_template(tera: &Tera, args: &Args) -> String {
+    let event = match args.r#type {
+        EventType::Host => "host",
+        EventType::Service => "service",
+    };
+    let kind = args.notification_type.to_string().to_lowercase();
+
+    let candidates = [
+        format!("{event}-{kind}.txt"),
+        format!("{kind}.txt"),
+        "template.txt".to_string(),
+    ];
+
+    let loaded: Vec<&str> = tera.get_template_names().collect();
+    candidates
+        .into_iter()
+        .find(|name| loaded.contains(&name.as_str()))
+        .unwrap_or_else(|| "template.txt".to_string())
+}
+
+impl Notifier for Email {
+    fn deliver(
+        &self,
+        args: &Args,
+        subject: &str,
+        body: &str,
+        body_type: BodyType,
+    ) -> Result<(), String> {
+        match self.send(&args.addresses, subject, body, body_type) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                // Don't drop the notification: park it on disk so a later
+                // invocation can retry once the relay recovers.
+                if let Err(spool_err) = spool(self, &args.addresses, subject, body, body_type) {
+                    error!("failed to spool undelivered mail: {spool_err}");
+                }
+                Err(e)
+            }
+        }
+    }
+}
 
-    let subject = subject(args);
+impl Email {
+    /// Build and send a single message synchronously. Shared by the live
+    /// delivery path and the spool replay path.
+    fn send(
+        &self,
+        addresses: &str,
+        subject: &str,
+        body: &str,
+        body_type: BodyType,
+    ) -> Result<(), String> {
+        let from = self.from.clone();
+
+        let mailboxes: Mailboxes = addresses.parse().map_err(|e| format!("{e:?}"))?;
+        let to_header: header::To = mailboxes.into();
+
+        let content_type = match body_type {
+            BodyType::Text => ContentType::TEXT_PLAIN,
+            BodyType::Html => ContentType::TEXT_HTML,
+        };
+
+        let email = Message::builder()
+            .from(from.parse().map_err(|e| format!("{e:?}"))?)
+            .reply_to(from.parse().map_err(|e| format!("{e:?}"))?)
+            .mailbox(to_header)
+            .subject(subject)
+            .header(content_type)
+            .header(mailer_name())
+            .body(String::from(body))
+            .map_err(|e| format!("{e:?}"))?;
+
+        debug!("relay host: {} ({:?})", &self.host, self.encryption);
+
+        let mut builder = match self.encryption {
+            TlsMode::Implicit => SmtpTransport::relay(&self.host).map_err(|e| format!("{e:?}"))?,
+            TlsMode::StartTls => {
+                SmtpTransport::starttls_relay(&self.host).map_err(|e| format!("{e:?}"))?
+            }
+            TlsMode::None => SmtpTransport::builder_dangerous(&self.host),
+        };
+
+        if let Some(port) = self.port {
+            builder = builder.port(port);
+        }
 
-    info!("subject: {}", subject);
+        // Only authenticate when a user/password pair is configured;
+        // unauthenticated internal relays leave both unset.
+        if let (Some(user), Some(password)) = (&self.user_name, &self.password) {
+            builder = builder.credentials(Credentials::new(user.clone(), password.clone()));
+            if let Some(auth) = self.auth {
+                builder = builder.authentication(vec![auth.into()]);
+            }
+        }
+
+        let mailer = builder.build();
+
+        match mailer.send(&email) {
+            Ok(_) => {
+                info!("Email sent successfully");
+                Ok(())
+            }
+            Err(e) => {
+                error!("Could not send email: {e:?}");
+                Err(format!("{e:?}"))
+            }
+        }
+    }
+}
 
-    let email = Message::builder()
-        .from(from.parse().unwrap())
-        .reply_to(from.parse().unwrap())
-        .mailbox(to_header)
-        .subject(subject)
-        .header(ContentType::TEXT_PLAIN)
-        .header(mailer_name())
-        .body(String::from(body))
-        .unwrap();
-
-    let creds = Credentials::new(
-        config.smtp.user_name.to_owned(),
-        config.smtp.password.to_owned(),
-    );
-
-    debug!("relay host: {}", &config.smtp.host);
-
-    let mailer = SmtpTransport::relay(&config.smtp.host)
-        .unwrap()
-        .credentials(creds)
-        .build();
-
-    match mailer.send(&email) {
-        Ok(_) => {
-            info!("Email sent successfully");
+impl Notifier for Webhook {
+    fn deliver(
+        &self,
+        args: &Args,
+        subject: &str,
+        body: &str,
+        _body_type: BodyType,
+    ) -> Result<(), String> {
+        let _ = body;
+
+        let payload = WebhookPayload {
+            args,
+            title: title(args),
+            subject,
+            status_description: status_description(args),
+        };
+
+        debug!("webhook url: {}", &self.url);
+
+        let response = reqwest::blocking::Client::new()
+            .post(&self.url)
+            .json(&payload)
+            .send()
+            .map_err(|e| format!("{e:?}"))?;
+
+        if response.status().is_success() {
+            info!("Webhook delivered successfully");
             Ok(())
+        } else {
+            let status = response.status();
+            error!("Could not deliver webhook: {status}");
+            Err(format!("unexpected status: {status}"))
+        }
+    }
+}
+
+const DEDUP_STATE: &str = "./state/dedup.json";
+
+/// What was last sent for a given incident, used to squelch repeated PROBLEM
+/// notifications while the incident persists.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DedupRecord {
+    fingerprint: String,
+    timestamp: i64,
+}
+
+/// Stable identity of an incident, constant across the PROBLEM/RECOVERY pair so
+/// a RECOVERY can clear the matching PROBLEM.
+fn incident_key(args: &Args) -> String {
+    format!(
+        "{:?}|{}|{}",
+        args.r#type,
+        args.host,
+        args.service.clone().unwrap_or_default()
+    )
+}
+
+/// Fingerprint of the exact event, including its type and status.
+fn fingerprint(args: &Args) -> String {
+    format!(
+        "{:?}|{}|{}|{}|{:?}",
+        args.r#type,
+        args.host,
+        args.service.clone().unwrap_or_default(),
+        args.notification_type,
+        args.status
+    )
+}
+
+/// Decide whether this notification should be suppressed, updating the dedup
+/// state file accordingly. RECOVERY always returns `false` and clears the
+/// stored fingerprint so the next PROBLEM is delivered immediately.
+fn suppress(args: &Args, window: i64) -> bool {
+    let mut state: HashMap<String, DedupRecord> = std::fs::read_to_string(DEDUP_STATE)
+        .ok()
+        .and_then(|raw| serde_json::from_str(&raw).ok())
+        .unwrap_or_default();
+
+    let key = incident_key(args);
+    let now = Utc::now().timestamp();
+
+    let suppressed = match args.notification_type {
+        NotificationType::Recovery => {
+            state.remove(&key);
+            false
+        }
+        NotificationType::Problem => {
+            let fp = fingerprint(args);
+            match state.get(&key) {
+                Some(prev) if prev.fingerprint == fp && now - prev.timestamp < window => true,
+                _ => {
+                    state.insert(
+                        key,
+                        DedupRecord {
+                            fingerprint: fp,
+                            timestamp: now,
+                        },
+                    );
+                    false
+                }
+            }
+        }
+    };
+
+    if let Err(e) = save_dedup_state(&state) {
+        error!("failed to persist dedup state: {e}");
+    }
+
+    suppressed
+}
+
+fn save_dedup_state(state: &HashMap<String, DedupRecord>) -> std::io::Result<()> {
+    if let Some(parent) = Path::new(DEDUP_STATE).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let json = serde_json::to_string_pretty(state)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    std::fs::write(DEDUP_STATE, json)
+}
+
+const SPOOL_DIR: &str = "./spool";
+
+/// Wait, in seconds, before the Nth retry. Attempts beyond the last entry
+/// reuse the final (capped) interval.
+const BACKOFF_SECS: [i64; 3] = [60, 300, 1800];
+
+/// Total attempts (live + replays) before a message is declared dead.
+const MAX_ATTEMPTS: u32 = 4;
+
+/// A self-describing, replay-able record of an undelivered message. Everything
+/// needed to retry after a restart — including the backend's own credentials —
+/// lives in the file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SpoolEntry {
+    email: Email,
+    addresses: String,
+    subject: String,
+    body: String,
+    #[serde(default)]
+    html: bool,
+    attempts: u32,
+    next_attempt: i64,
+}
+
+impl SpoolEntry {
+    /// Seconds to wait after `attempts` failures before the next try.
+    fn backoff(attempts: u32) -> i64 {
+        let idx = (attempts.max(1) - 1) as usize;
+        BACKOFF_SECS[idx.min(BACKOFF_SECS.len() - 1)]
+    }
+}
+
+/// Park an undelivered message in the spool directory for later replay.
+fn spool(
+    email: &Email,
+    addresses: &str,
+    subject: &str,
+    body: &str,
+    body_type: BodyType,
+) -> std::io::Result<()> {
+    std::fs::create_dir_all(SPOOL_DIR)?;
+
+    let entry = SpoolEntry {
+        email: email.clone(),
+        addresses: addresses.to_string(),
+        subject: subject.to_string(),
+        body: body.to_string(),
+        html: body_type == BodyType::Html,
+        attempts: 1,
+        next_attempt: Utc::now().timestamp() + SpoolEntry::backoff(1),
+    };
+
+    let name = format!("{}-{}.json", Utc::now().timestamp_nanos_opt().unwrap_or(0), std::process::id());
+    let path = Path::new(SPOOL_DIR).join(name);
+
+    write_entry(&path, &entry)?;
+    info!("spooled undelivered mail to {}", path.display());
+
+    Ok(())
+}
+
+/// Serialize an entry to a temp file and atomically rename it into place so a
+/// concurrent reader never observes a half-written record.
+fn write_entry(path: &Path, entry: &SpoolEntry) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let json = serde_json::to_string_pretty(entry)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    std::fs::write(&tmp, json)?;
+    std::fs::rename(&tmp, path)
+}
+
+/// Replay every due spool entry. Each invocation runs this first, so a backed
+/// up queue drains as soon as the relay recovers.
+fn drain_spool() {
+    let dir = match std::fs::read_dir(SPOOL_DIR) {
+        Ok(dir) => dir,
+        Err(_) => return, // nothing spooled yet
+    };
+
+    let now = Utc::now().timestamp();
+
+    for item in dir.flatten() {
+        let path = item.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+
+        let entry = match read_entry(&path) {
+            Ok(entry) => entry,
+            Err(e) => {
+                error!("skipping unreadable spool entry {}: {e}", path.display());
+                continue;
+            }
+        };
+
+        if entry.next_attempt > now {
+            continue; // not due yet
+        }
+
+        // Claim the entry by renaming it out of the way; if another invocation
+        // renamed it first the rename fails and we skip it — no double-send.
+        let claim = path.with_extension(format!("json.{}.processing", std::process::id()));
+        if std::fs::rename(&path, &claim).is_err() {
+            continue;
+        }
+
+        retry_entry(&path, &claim, entry);
+    }
+}
+
+/// Attempt a single claimed entry, then dispose of the claim file according to
+/// the outcome: delete on success, re-spool with backoff on transient failure,
+/// or bury in `dead/` once it is exhausted.
+fn retry_entry(original: &Path, claim: &Path, mut entry: SpoolEntry) {
+    let body_type = if entry.html {
+        BodyType::Html
+    } else {
+        BodyType::Text
+    };
+
+    match entry
+        .email
+        .send(&entry.addresses, &entry.subject, &entry.body, body_type)
+    {
+        Ok(()) => {
+            info!("replayed spooled mail: {}", entry.subject);
+            let _ = std::fs::remove_file(claim);
         }
         Err(e) => {
-            error!("Could not send email: {e:?}");
-            Err(e)
+            entry.attempts += 1;
+            if entry.attempts >= MAX_ATTEMPTS {
+                error!("giving up on spooled mail after {} attempts: {e}", entry.attempts);
+                bury(claim, &entry);
+            } else {
+                entry.next_attempt = Utc::now().timestamp() + SpoolEntry::backoff(entry.attempts);
+                if let Err(e) = write_entry(original, &entry) {
+                    error!("failed to re-spool entry: {e}");
+                }
+                let _ = std::fs::remove_file(claim);
+            }
         }
     }
 }
 
+/// Move an exhausted entry into the `dead/` folder for manual inspection.
+fn bury(claim: &Path, entry: &SpoolEntry) {
+    let dead_dir = Path::new(SPOOL_DIR).join("dead");
+    if let Err(e) = std::fs::create_dir_all(&dead_dir) {
+        error!("failed to create dead-letter dir: {e}");
+        return;
+    }
+
+    let name: PathBuf = claim.file_name().map(PathBuf::from).unwrap_or_default();
+    let dead = dead_dir.join(name.with_extension("json"));
+    if let Err(e) = write_entry(&dead, entry) {
+        error!("failed to write dead-letter entry: {e}");
+        return;
+    }
+    let _ = std::fs::remove_file(claim);
+}
+
+fn read_entry(path: &Path) -> Result<SpoolEntry, String> {
+    let raw = std::fs::read_to_string(path).map_err(|e| format!("{e}"))?;
+    serde_json::from_str(&raw).map_err(|e| format!("{e}"))
+}
+
 fn mailer_name() -> UserAgent {
     UserAgent::from(format!("Nagios Notify/{}", env!("CARGO_PKG_VERSION")))
 }